@@ -3,10 +3,13 @@ extern crate git2;
 extern crate log;
 
 use std::error::Error as StdError;
+use std::io::Write;
 use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
 use std::fs;
 use git2::{Config, Repository, Signature, Error, PushOptions, RemoteCallbacks, BranchType,
            ResetType, ObjectType};
+use git2::build::CheckoutBuilder;
 use url::Url;
 use utils::{with_authentication, fetch};
 
@@ -17,6 +20,17 @@ pub struct Author {
     pub email: String,
 }
 
+/// Which kind of tag to create with [`tag`](fn.tag.html).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TagKind {
+    /// A plain ref pointing directly at the target, with no tag object.
+    Lightweight,
+    /// A tag object carrying a tagger and message (the default `git tag -a`).
+    Annotated,
+    /// An annotated tag, additionally detached-signed with GPG.
+    Signed,
+}
+
 pub fn get_signature() -> Result<Author, Error> {
     let config = try!(Config::open_default());
     let author = try!(config.get_string("user.name"));
@@ -61,13 +75,99 @@ pub fn commit(repo: &str, name: &str, email: &str, message: &str) -> Result<(),
         .map(|_| ())
 }
 
-pub fn tag(repo: &str, name: &str, email: &str, tag_name: &str, message: &str) -> Result<(), Error> {
+fn has_tag(repo: &Repository, tag_name: &str) -> Result<bool, Error> {
+    let tagnames = try!(repo.tag_names(Some(tag_name)));
+
+    Ok(tagnames.iter().any(|t| {
+        match t {
+            None => false,
+            Some(ref t) => *t == tag_name,
+        }
+    }))
+}
+
+fn format_signature(sig: &Signature) -> String {
+    let when = sig.when();
+    let offset = when.offset_minutes();
+    let sign = if offset < 0 { '-' } else { '+' };
+
+    format!("{} <{}> {} {}{:02}{:02}",
+            sig.name().unwrap_or(""),
+            sig.email().unwrap_or(""),
+            when.seconds(),
+            sign,
+            offset.abs() / 60,
+            offset.abs() % 60)
+}
+
+fn sign_tag_buffer(repo: &Repository, buffer: &str) -> Result<String, Error> {
+    let config = try!(repo.config());
+    let program = config.get_string("gpg.program").unwrap_or_else(|_| "gpg".to_string());
+
+    let mut child = try!(Command::new(&program)
+        .arg("--detach-sign")
+        .arg("--armor")
+        .arg("--output").arg("-")
+        .arg("-")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .map_err(|e| Error::from_str(&format!("failed to run '{}': {}", program, e))));
+
+    {
+        let stdin = child.stdin.as_mut().expect("stdin was piped");
+        try!(stdin.write_all(buffer.as_bytes())
+             .map_err(|e| Error::from_str(&format!("failed to write to '{}': {}", program, e))));
+    }
+
+    let output = try!(child.wait_with_output()
+        .map_err(|e| Error::from_str(&format!("failed to wait on '{}': {}", program, e))));
+
+    if !output.status.success() {
+        return Err(Error::from_str(&format!("'{}' failed to sign the tag", program)));
+    }
+
+    String::from_utf8(output.stdout)
+        .map_err(|e| Error::from_str(&format!("'{}' produced invalid UTF-8: {}", program, e)))
+}
+
+pub fn tag(repo: &str, name: &str, email: &str, tag_name: &str, message: &str,
+           kind: TagKind, force: bool) -> Result<(), Error> {
     let repo = try!(Repository::open(repo));
+
+    if !force && try!(has_tag(&repo, tag_name)) {
+        return Err(Error::from_str(&format!("Tag '{}' already exists", tag_name)));
+    }
+
     let obj = try!(repo.revparse_single("HEAD"));
-    let signature = try!(Signature::now(name, email));
 
-    repo.tag(tag_name, &obj, &signature, message, false)
-        .map(|_| ())
+    match kind {
+        TagKind::Lightweight => {
+            repo.tag_lightweight(tag_name, &obj, force).map(|_| ())
+        }
+
+        TagKind::Annotated => {
+            let signature = try!(Signature::now(name, email));
+            repo.tag(tag_name, &obj, &signature, message, force)
+                .map(|_| ())
+        }
+
+        TagKind::Signed => {
+            let signature = try!(Signature::now(name, email));
+            let target_kind = obj.kind().map(|k| k.str()).unwrap_or("commit");
+            let buffer = format!("object {}\ntype {}\ntag {}\ntagger {}\n\n{}\n",
+                                  obj.id(), target_kind, tag_name, format_signature(&signature), message);
+            let signed = try!(sign_tag_buffer(&repo, &buffer));
+            let buffer = format!("{}{}", buffer, signed);
+
+            let odb = try!(repo.odb());
+            let oid = try!(odb.write(ObjectType::Tag, buffer.as_bytes()));
+
+            let refname = format!("refs/tags/{}", tag_name);
+            repo.reference(&refname, oid, force, "tag: signed")
+                .map(|_| ())
+        }
+    }
 }
 
 fn ref_tag_or_branch(repo: &Repository, names: &[String]) -> Result<Vec<String>, Error> {
@@ -97,7 +197,8 @@ fn ref_tag_or_branch(repo: &Repository, names: &[String]) -> Result<Vec<String>,
     })
 }
 
-pub fn push(repo: &str, remote_name: &str, branches: &[String]) -> Result<(), Error> {
+pub fn push(repo: &str, remote_name: &str, branches: &[String],
+            mut progress: Option<&mut FnMut(usize, usize, usize)>) -> Result<(), Error> {
     let repo = try!(Repository::open(repo));
     let config = try!(repo.config());
 
@@ -110,6 +211,11 @@ pub fn push(repo: &str, remote_name: &str, branches: &[String]) -> Result<(), Er
     with_authentication(remote_url, &config, |f| {
         let mut cbs = RemoteCallbacks::new();
         cbs.credentials(f);
+        if let Some(ref mut progress) = progress {
+            cbs.push_transfer_progress(|current, total, bytes| {
+                progress(current, total, bytes);
+            });
+        }
         let mut opts = PushOptions::new();
         opts.remote_callbacks(cbs);
 
@@ -120,6 +226,69 @@ pub fn push(repo: &str, remote_name: &str, branches: &[String]) -> Result<(), Er
     })
 }
 
+/// Update `branch` from `remote_name`, fast-forwarding the local ref if
+/// possible.
+///
+/// If the fetched history has diverged from the local branch, this returns
+/// an error rather than attempting a content merge.
+///
+/// If the local branch is already up to date, nothing is changed. Otherwise
+/// `branch` ends up checked out afterwards regardless of what was checked
+/// out before the call, since fast-forwarding moves the ref and then
+/// updates `HEAD` and the working tree to match it. This is only really
+/// sensible when `branch` is (or was) the currently checked-out branch;
+/// pulling some other branch will switch the repository to it.
+pub fn pull(repo: &str, remote_name: &str, branch: &str) -> Result<(), Error> {
+    let repo = try!(Repository::open(repo));
+
+    let remote = try!(repo.find_remote(remote_name));
+    let remote_url = match remote.url() {
+        Some(url) => url,
+        None => return Err(Error::from_str(&format!("No remote URL found for '{}'", remote_name))),
+    };
+
+    let refspec = format!("refs/heads/{}", branch);
+    try!(fetch(&repo, remote_url, &refspec, None, None, false));
+
+    let fetch_head = try!(repo.find_reference("FETCH_HEAD"));
+    let fetch_commit = try!(repo.reference_to_annotated_commit(&fetch_head));
+
+    let refname = format!("refs/heads/{}", branch);
+
+    // Analyze against the tip of the branch we're actually updating, not
+    // whatever happens to be checked out as HEAD right now. Requires a git2
+    // new enough to expose `merge_analysis_for_ref` (it mirrors libgit2's
+    // `git_merge_analysis_for_ref`, alongside the older HEAD-only
+    // `merge_analysis`/`git_merge_analysis`).
+    if let Ok(local_ref) = repo.find_reference(&refname) {
+        let analysis = try!(repo.merge_analysis_for_ref(&local_ref, &[&fetch_commit]));
+
+        if analysis.0.is_up_to_date() {
+            return Ok(());
+        }
+
+        if !analysis.0.is_fast_forward() {
+            return Err(Error::from_str(
+                "Cannot fast-forward; local branch has diverged and needs a real merge"));
+        }
+    }
+
+    let target = fetch_commit.id();
+
+    match repo.find_reference(&refname) {
+        Ok(mut r) => {
+            try!(r.set_target(target, "Fast-forward"));
+        }
+        Err(_) => {
+            try!(repo.reference(&refname, target, true, "Fast-forward"));
+        }
+    }
+
+    try!(repo.set_head(&refname));
+    let obj = try!(repo.find_object(target, None));
+    repo.checkout_tree(&obj, Some(CheckoutBuilder::default()))
+}
+
 pub fn branch(repo: &str, branch_type: BranchType) -> Result<Vec<String>, Error> {
     let repo = try!(Repository::open(repo));
 
@@ -152,7 +321,10 @@ pub fn branch(repo: &str, branch_type: BranchType) -> Result<Vec<String>, Error>
     Ok(v)
 }
 
-pub fn clone<S: AsRef<str>>(url: &str, directory: Option<S>) -> Result<(), Error> {
+pub fn clone<S: AsRef<str>>(url: &str, directory: Option<S>,
+                             progress: Option<&mut FnMut(git2::Progress)>,
+                             depth: Option<u32>, branch: Option<String>,
+                             cli_fallback: bool) -> Result<(), Error> {
     let parsed_url = try!(Url::parse(url).map_err(|e| Error::from_str(e.description())));
 
     let dst = match directory {
@@ -178,10 +350,29 @@ pub fn clone<S: AsRef<str>>(url: &str, directory: Option<S>) -> Result<(), Error
     try!(fs::create_dir_all(&dst).map_err(|e| Error::from_str(e.description())));
     let repo = try!(git2::Repository::init(&dst));
 
-    try!(fetch(&repo, url, "refs/heads/*:refs/heads/*"));
-    let head = try!(repo.head());
-    let head_obj = try!(head.peel(ObjectType::Commit));
-    try!(repo.reset(&head_obj, ResetType::Hard, None));
+    let refspec = match branch {
+        Some(ref branch) => format!("refs/heads/{0}:refs/heads/{0}", branch),
+        None => "refs/heads/*:refs/heads/*".to_string(),
+    };
+
+    try!(fetch(&repo, url, &refspec, progress, depth, cli_fallback));
+
+    let target_obj = match branch {
+        Some(ref branch) => {
+            let reference = try!(repo.find_reference(&format!("refs/heads/{}", branch)));
+            try!(reference.peel(ObjectType::Commit))
+        }
+        None => {
+            let head = try!(repo.head());
+            try!(head.peel(ObjectType::Commit))
+        }
+    };
+    try!(repo.reset(&target_obj, ResetType::Hard, None));
+
+    if let Some(branch) = branch {
+        try!(repo.set_head(&format!("refs/heads/{}", branch)));
+    }
+
     try!(repo.remote("origin", url));
 
     Ok(())
@@ -6,7 +6,7 @@ extern crate log;
 extern crate env_logger;
 
 use structopt::StructOpt;
-use git2::{Error, BranchType};
+use git2::{Error, BranchType, Progress};
 
 /// git2-commit - Simple git commands, reimplemented.
 #[derive(Debug, StructOpt)]
@@ -45,6 +45,15 @@ enum Command {
         tag: String,
         /// Message for the new tag
         message: String,
+        /// Create a GPG-signed tag
+        #[structopt(short = "s", long = "sign")]
+        sign: bool,
+        /// Create a lightweight tag instead of an annotated one
+        #[structopt(long = "lightweight")]
+        lightweight: bool,
+        /// Replace an existing tag of the same name
+        #[structopt(short = "f", long = "force")]
+        force: bool,
     },
 
     /// Push local commits to a remote
@@ -57,6 +66,15 @@ enum Command {
 
     },
 
+    /// Fetch from a remote and fast-forward the local branch
+    #[structopt(name = "pull")]
+    Pull {
+        /// Remote to pull from
+        remote: String,
+        /// Branch to update
+        branch: String,
+    },
+
     /// List branches
     #[structopt(name = "branch")]
     Branch {
@@ -72,9 +90,41 @@ enum Command {
         url: String,
         /// Directory to clone to [default: .]
         directory: Option<String>,
+        /// Create a shallow clone with a history truncated to the given number of commits
+        #[structopt(long = "depth")]
+        depth: Option<u32>,
+        /// Clone only a single branch instead of every branch
+        #[structopt(short = "b", long = "branch")]
+        branch: Option<String>,
+        /// Fall back to the system git binary if libgit2's transport fails
+        #[structopt(long = "cli-fetch")]
+        cli_fetch: bool,
     },
 }
 
+/// Default renderer for `fetch`/`clone` transfer progress: a single
+/// overwriting line on stderr, e.g. `Receiving objects:  42% (420/1000), 3.2 MiB`.
+fn print_fetch_progress(stats: Progress) {
+    let total = stats.total_objects();
+    let received = stats.received_objects();
+    let pct = if total > 0 { received * 100 / total } else { 0 };
+    let mib = stats.received_bytes() as f64 / (1024.0 * 1024.0);
+    eprint!("\rReceiving objects: {:3}% ({}/{}), {:.1} MiB", pct, received, total, mib);
+    if received == total {
+        eprintln!();
+    }
+}
+
+/// Default renderer for `push` transfer progress.
+fn print_push_progress(current: usize, total: usize, bytes: usize) {
+    let pct = if total > 0 { current * 100 / total } else { 0 };
+    let mib = bytes as f64 / (1024.0 * 1024.0);
+    eprint!("\rWriting objects: {:3}% ({}/{}), {:.1} MiB", pct, current, total, mib);
+    if current == total {
+        eprintln!();
+    }
+}
+
 fn run(git: Git) -> Result<(), Error> {
     let repo = git.path.unwrap_or_else(|| ".".to_string());
 
@@ -89,13 +139,24 @@ fn run(git: Git) -> Result<(), Error> {
             git2_commit::commit(&repo, &signature.name, &signature.email, &message)
         },
 
-        Tag { tag, message } => {
+        Tag { tag, message, sign, lightweight, force } => {
             let signature = try!(git2_commit::get_signature());
-            git2_commit::tag(&repo, &signature.name, &signature.email, &tag, &message)
+            let kind = if sign {
+                git2_commit::TagKind::Signed
+            } else if lightweight {
+                git2_commit::TagKind::Lightweight
+            } else {
+                git2_commit::TagKind::Annotated
+            };
+            git2_commit::tag(&repo, &signature.name, &signature.email, &tag, &message, kind, force)
         },
 
         Push { remote, branches } => {
-            git2_commit::push(&repo, &remote, &branches)
+            git2_commit::push(&repo, &remote, &branches, Some(&mut print_push_progress))
+        },
+
+        Pull { remote, branch } => {
+            git2_commit::pull(&repo, &remote, &branch)
         },
 
         Branch { remotes } => {
@@ -115,8 +176,8 @@ fn run(git: Git) -> Result<(), Error> {
             Ok(())
         },
 
-        Clone { url, directory } => {
-            git2_commit::clone(&url, directory)
+        Clone { url, directory, depth, branch, cli_fetch } => {
+            git2_commit::clone(&url, directory, Some(&mut print_fetch_progress), depth, branch, cli_fetch)
         },
     }
 }
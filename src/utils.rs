@@ -1,5 +1,50 @@
 use git2;
 use std::env;
+use std::path::PathBuf;
+use std::process::Command;
+use std::time::{Duration, Instant};
+
+/// Usernames to try, in order, when libgit2 asks us to restart the SSH
+/// session with a fresh one: the credential helper's configured username,
+/// then the local account's username, then "git" as a last resort.
+fn restart_username_candidates(cred_helper: &git2::CredentialHelper) -> Vec<String> {
+    let mut candidates = Vec::new();
+    if let Some(ref name) = cred_helper.username {
+        candidates.push(name.clone());
+    }
+    if let Ok(name) = env::var("USER").or_else(|_| env::var("USERNAME")) {
+        candidates.push(name);
+    }
+    candidates.push("git".to_string());
+    candidates
+}
+
+/// Candidate SSH private/public key file pairs to try, in order, when no
+/// ssh-agent is available (or its keys are rejected).
+///
+/// `GIT2_COMMIT_SSH_KEY` (and optionally `GIT2_COMMIT_SSH_PUBKEY`) pin this to
+/// a single explicit pair; otherwise we fall back to the usual default
+/// locations under `~/.ssh`, skipping any that don't exist on disk.
+fn ssh_key_candidates() -> Vec<(PathBuf, Option<PathBuf>)> {
+    if let Ok(key) = env::var("GIT2_COMMIT_SSH_KEY") {
+        let pubkey = env::var("GIT2_COMMIT_SSH_PUBKEY").ok().map(PathBuf::from);
+        return vec![(PathBuf::from(key), pubkey)];
+    }
+
+    let home = match env::var("HOME").or_else(|_| env::var("USERPROFILE")) {
+        Ok(home) => PathBuf::from(home),
+        Err(_) => return vec![],
+    };
+
+    ["id_ed25519", "id_rsa"].iter()
+        .map(|name| {
+            let private = home.join(".ssh").join(name);
+            let public = home.join(".ssh").join(format!("{}.pub", name));
+            (private, Some(public))
+        })
+        .filter(|&(ref private, _)| private.exists())
+        .collect()
+}
 
 /// Adopted from Cargo's `git/utils.rs`
 /// See
@@ -33,47 +78,41 @@ use std::env;
 /// credentials until we give it a reason to not do so. To ensure we don't
 /// just sit here looping forever we keep track of authentications we've
 /// attempted and we don't try the same ones again.
+///
+/// SSH is a special case: libgit2/libssh2 only allow one username per
+/// authentication session, so once libgit2 asks us which username to use
+/// (rather than already knowing one from the URL) we can't just cycle
+/// through candidates inside a single credentials callback -- that session
+/// is stuck with whichever username we first offered it. Instead, following
+/// cargo's fix for this (rust-lang/cargo#2584), we call `f` again from
+/// scratch for every username and on-disk key we want to try, so libgit2
+/// restarts the handshake each time.
 pub fn with_authentication<F>(url: &str, cfg: &git2::Config, mut f: F)
                              -> Result<(), git2::Error>
     where F: FnMut(&mut git2::Credentials) -> Result<(), git2::Error>
 {
-    // We try a couple of different user names when cloning via ssh as there's a
-    // few possibilities if one isn't mentioned, and these are used to keep
-    // track of that.
-    enum UsernameAttempt {
-        Arg,
-        CredHelper,
-        Local,
-        Git,
-    }
-
     let mut cred_helper = git2::CredentialHelper::new(url);
     cred_helper.config(cfg);
 
     let mut attempted = git2::CredentialType::empty();
     let mut failed_cred_helper = false;
-    let mut username_attempt = UsernameAttempt::Arg;
-    let mut username_attempts = Vec::new();
+    let mut ssh_username_requested = false;
+    let mut tried_sshkey = false;
+    let mut initial_username: Option<String> = None;
+    let mut username_attempts: Vec<String> = Vec::new();
 
-    f(&mut |url, username, allowed| {
+    let mut res = f(&mut |url, username, allowed| {
         let allowed = allowed & !attempted;
 
         // libgit2's "USERNAME" authentication actually means that it's just
-        // asking us for a username to keep going. This is currently only really
-        // used for SSH authentication and isn't really an authentication type.
-        // The logic currently looks like:
-        //
-        //      let user = ...;
-        //      if (user.is_null())
-        //          user = callback(USERNAME, null, ...);
-        //
-        //      callback(SSH_KEY, user, ...)
-        //
-        // So if we have a USERNAME request we just pass it either `username` or
-        // a fallback of "git". We'll do some more principled attempts later on.
+        // asking us for a username to keep going, because no username was
+        // given in the URL. We can't just answer it and carry on to SSH_KEY
+        // in the same session (see the note above), so bail out of this
+        // session entirely and let the restart loop below try usernames one
+        // at a time.
         if allowed.contains(git2::USERNAME) {
-            attempted = attempted | git2::USERNAME;
-            return git2::Cred::username(username.unwrap_or("git"))
+            ssh_username_requested = true;
+            return Err(git2::Error::from_str("restarting to try usernames one at a time"))
         }
 
         // If User and password in plaintext is allowed
@@ -85,44 +124,15 @@ pub fn with_authentication<F>(url: &str, cfg: &git2::Config, mut f: F)
             }
         }
 
-        // An "SSH_KEY" authentication indicates that we need some sort of SSH
-        // authentication. This can currently either come from the ssh-agent
-        // process or from a raw in-memory SSH key. Cargo only supports using
-        // ssh-agent currently.
-        //
-        // We try a few different usernames here, including:
-        //
-        //  1. The `username` argument, if provided. This will cover cases where
-        //     the user was passed in the URL, for example.
-        //  2. The global credential helper's username, if any is configured
-        //  3. The local account's username (if present)
-        //  4. Finally, "git" as it's a common fallback (e.g. with github)
-        if allowed.contains(git2::SSH_KEY) {
-            loop {
-                let name = match username_attempt {
-                    UsernameAttempt::Arg => {
-                        username_attempt = UsernameAttempt::CredHelper;
-                        username.map(|s| s.to_string())
-                    }
-                    UsernameAttempt::CredHelper => {
-                        username_attempt = UsernameAttempt::Local;
-                        cred_helper.username.clone()
-                    }
-                    UsernameAttempt::Local => {
-                        username_attempt = UsernameAttempt::Git;
-                        env::var("USER").or_else(|_| env::var("USERNAME")).ok()
-                    }
-                    UsernameAttempt::Git => {
-                        attempted = attempted | git2::SSH_KEY;
-                        Some("git".to_string())
-                    }
-                };
-                if let Some(name) = name {
-                    let ret = git2::Cred::ssh_key_from_agent(&name);
-                    username_attempts.push(name);
-                    return ret
-                }
-            }
+        // A username was already given (e.g. in the URL), so we know we only
+        // get one shot at this session: try the ssh-agent for it once.
+        if allowed.contains(git2::SSH_KEY) && !tried_sshkey {
+            tried_sshkey = true;
+            let name = username.unwrap_or("git").to_string();
+            let ret = git2::Cred::ssh_key_from_agent(&name);
+            username_attempts.push(format!("ssh-agent:{}", name));
+            initial_username = Some(name);
+            return ret
         }
 
         // Sometimes libgit2 will ask for a username/password in plaintext. This
@@ -146,13 +156,164 @@ pub fn with_authentication<F>(url: &str, cfg: &git2::Config, mut f: F)
 
         // Whelp, we tried our best
         Err(git2::Error::from_str("no authentication available"))
-    })
+    });
+
+    // We try a couple of different user names when cloning via ssh as
+    // there's a few possibilities if one isn't mentioned:
+    //
+    //  1. The global credential helper's username, if any is configured
+    //  2. The local account's username (if present)
+    //  3. Finally, "git" as it's a common fallback (e.g. with github)
+    //
+    // Each candidate gets its own fresh session (a fresh call to `f`): we
+    // answer the USERNAME request with it, then try the ssh-agent for it.
+    // SSH key authorization is per-account (`git@host` and `alice@host` are
+    // different identities to the server), so a rejection for one username
+    // tells us nothing about the others -- only stop once one of them
+    // actually succeeds. The candidate list is bounded (at most 3 entries),
+    // so this can't loop forever.
+    if ssh_username_requested {
+        let candidates = restart_username_candidates(&cred_helper);
+
+        for name in candidates {
+            let mut offered_agent = false;
+            res = f(&mut |_url, _username, allowed| {
+                if allowed.contains(git2::USERNAME) {
+                    return git2::Cred::username(&name)
+                }
+                if allowed.contains(git2::SSH_KEY) && !offered_agent {
+                    offered_agent = true;
+                    username_attempts.push(format!("ssh-agent:{}", name));
+                    return git2::Cred::ssh_key_from_agent(&name)
+                }
+                Err(git2::Error::from_str("no authentication available"))
+            });
+
+            if res.is_ok() {
+                break;
+            }
+        }
+    }
+
+    // If every ssh-agent attempt above failed, or there was no agent running
+    // at all, fall back to on-disk key files, trying each one in its own
+    // fresh session for the same reason as the usernames above. When we had
+    // to restart per-username, key files need the same treatment: a key
+    // rejected for "git" tells us nothing about "alice", so try every
+    // username candidate against every key file rather than pinning to
+    // whichever username the ssh-agent loop happened to try last.
+    if res.is_err() && (tried_sshkey || ssh_username_requested) {
+        let usernames = if ssh_username_requested {
+            restart_username_candidates(&cred_helper)
+        } else {
+            vec![initial_username.clone().unwrap_or_else(|| "git".to_string())]
+        };
+
+        'key_files: for name in &usernames {
+            for &(ref private, ref public) in &ssh_key_candidates() {
+                let passphrase = env::var("GIT2_COMMIT_SSH_PASSPHRASE").ok();
+                username_attempts.push(format!("key-file:{} ({})", name, private.display()));
+
+                let mut offered_key = false;
+                res = f(&mut |_url, _username, allowed| {
+                    if allowed.contains(git2::USERNAME) {
+                        return git2::Cred::username(name)
+                    }
+                    if allowed.contains(git2::SSH_KEY) && !offered_key {
+                        offered_key = true;
+                        return git2::Cred::ssh_key(name,
+                                                    public.as_ref().map(|p| p.as_path()),
+                                                    private,
+                                                    passphrase.as_ref().map(|s| &s[..]))
+                    }
+                    Err(git2::Error::from_str("no authentication available"))
+                });
+
+                if res.is_ok() {
+                    break 'key_files;
+                }
+            }
+        }
+    }
+
+    match res {
+        Err(ref e) if !username_attempts.is_empty() => {
+            Err(git2::Error::from_str(&format!(
+                "failed to authenticate over SSH after trying: {} ({})",
+                username_attempts.join(", "), e)))
+        }
+        res => res,
+    }
+}
+
+/// Fetch `refspec` from `url` into `repo`.
+///
+/// If `progress` is given, it is called with the libgit2 transfer stats a few
+/// times per second while objects are downloaded, so callers can render their
+/// own progress indicator instead of leaving the user staring at a silent
+/// terminal on a big repo.
+///
+/// `depth`, if given, requests a shallow fetch of only the last `n` commits
+/// reachable from `refspec`. The git2 version this crate is pinned to
+/// predates `FetchOptions::depth` (added for libgit2 1.0's shallow-clone
+/// support), so libgit2 itself has no way to do a shallow fetch here -- a
+/// `depth` request is always routed straight through the system `git`
+/// binary instead, which has supported `--depth` for years.
+///
+/// If the libgit2 fetch fails with a network or SSH error and `cli_fallback`
+/// is set (or `GIT2_COMMIT_NET_GIT_FETCH` is set in the environment), falls
+/// back to shelling out to the system `git`, which handles proxies, newer
+/// TLS and SSH configurations that libgit2's built-in transports miss.
+pub fn fetch(repo: &git2::Repository, url: &str, refspec: &str,
+             mut progress: Option<&mut FnMut(git2::Progress)>,
+             depth: Option<u32>, cli_fallback: bool) -> Result<(), git2::Error> {
+    if depth.is_some() {
+        return fetch_via_git_cli(repo, url, refspec, depth);
+    }
+
+    let result = fetch_via_libgit2(repo, url, refspec, &mut progress);
+
+    match result {
+        Err(ref e) if is_net_or_ssh_error(e) &&
+            (cli_fallback || env::var("GIT2_COMMIT_NET_GIT_FETCH").is_ok()) => {
+            fetch_via_git_cli(repo, url, refspec, depth)
+        }
+        result => result,
+    }
 }
 
-pub fn fetch(repo: &git2::Repository, url: &str, refspec: &str) -> Result<(), git2::Error> {
+fn is_net_or_ssh_error(e: &git2::Error) -> bool {
+    match e.class() {
+        git2::ErrorClass::Net | git2::ErrorClass::Ssh => true,
+        _ => false,
+    }
+}
+
+fn fetch_via_libgit2(repo: &git2::Repository, url: &str, refspec: &str,
+                      progress: &mut Option<&mut FnMut(git2::Progress)>) -> Result<(), git2::Error> {
     with_authentication(url, &try!(repo.config()), |f| {
         let mut cb = git2::RemoteCallbacks::new();
         cb.credentials(f);
+
+        let mut last_update = Instant::now() - Duration::from_secs(1);
+        let mut finished = false;
+        if let Some(ref mut progress) = *progress {
+            cb.transfer_progress(|stats| {
+                // Report the moment the download completes exactly once; later
+                // calls (delta resolution) are not passed on.
+                if finished {
+                    return true;
+                }
+                let now = Instant::now();
+                finished = stats.received_objects() == stats.total_objects();
+                if finished || now.duration_since(last_update) >= Duration::from_millis(200) {
+                    last_update = now;
+                    progress(stats);
+                }
+                true
+            });
+        }
+
         let mut remote = try!(repo.remote_anonymous(&url));
         let mut opts = git2::FetchOptions::new();
         opts.remote_callbacks(cb)
@@ -161,3 +322,35 @@ pub fn fetch(repo: &git2::Repository, url: &str, refspec: &str) -> Result<(), gi
         Ok(())
     })
 }
+
+/// Fall back to the system `git` binary, which handles transports libgit2
+/// doesn't (proxies, newer TLS, unusual SSH configs, `insteadOf` rewrites).
+///
+/// `depth`, if given, is passed through as `--depth`, so a shallow fetch
+/// request is still honored when libgit2 couldn't do the fetch itself.
+///
+/// `GIT_TERMINAL_PROMPT=0` ensures a broken auth setup errors out instead of
+/// hanging on an interactive prompt.
+fn fetch_via_git_cli(repo: &git2::Repository, url: &str, refspec: &str,
+                      depth: Option<u32>) -> Result<(), git2::Error> {
+    let mut cmd = Command::new("git");
+    cmd.current_dir(repo.path())
+        .env("GIT_TERMINAL_PROMPT", "0")
+        .arg("fetch")
+        .arg(url)
+        .arg(refspec);
+    if let Some(depth) = depth {
+        cmd.arg("--depth").arg(depth.to_string());
+    }
+
+    let output = try!(cmd
+        .output()
+        .map_err(|e| git2::Error::from_str(&format!("failed to run system git: {}", e))));
+
+    if output.status.success() {
+        Ok(())
+    } else {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        Err(git2::Error::from_str(&format!("git fetch failed: {}", stderr.trim())))
+    }
+}